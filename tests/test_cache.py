@@ -0,0 +1,369 @@
+import hashlib
+import json
+import os
+import shutil
+import tempfile
+import threading
+import time
+import unittest
+
+from types import SimpleNamespace
+from unittest import mock
+
+from wcl import caching
+from wcl.caching import Cache
+
+def stub_query( string, **options ):
+  return SimpleNamespace( **{
+    'string': string,
+    'max_age': None,
+    'cacheable': True,
+    'cache_policy': 'cache_first',
+    'stale_while_revalidate': False,
+    **options
+  } )
+
+class StubRequest:
+  # counts live requests instead of making them
+  failures = ( OSError, )
+  fetches = 0
+  offline = False
+  fetch_lock = threading.Lock()
+
+  def __init__( self, query, data=None ):
+    self.query = query
+    self.data = self.get_request() if data is None else data
+
+  def get_request( self ):
+    if self.offline:
+      raise ConnectionError( 'network is down' )
+    with self.fetch_lock:
+      StubRequest.fetches += 1
+    time.sleep( 0.1 )
+    return {
+      'query': self.query.string,
+      'fetch': StubRequest.fetches
+    }
+
+class CacheTestCase( unittest.TestCase ):
+  def setUp( self ):
+    self.cache_root = tempfile.mkdtemp()
+    self.addCleanup( shutil.rmtree, self.cache_root )
+
+  def artifact_files( self ):
+    artifacts = os.path.join( self.cache_root, 'artifacts' )
+    return os.listdir( artifacts ) if os.path.isdir( artifacts ) else []
+
+class TestIndexValidation( CacheTestCase ):
+  def test_missing_index_is_created( self ):
+    Cache( self.cache_root )
+    self.assertEqual( caching.read_index( os.path.join( self.cache_root, 'index.json' ) ), [] )
+
+  def test_foreign_index_is_rejected( self ):
+    with open( os.path.join( self.cache_root, 'index.json' ), 'w' ) as handle:
+      handle.write( '{"not": "an index"}' )
+    with self.assertRaisesRegex( ValueError, 'is not a cache index' ):
+      Cache( self.cache_root )
+
+class TestIndexMerge( CacheTestCase ):
+  def test_additions_from_both_handles_are_kept( self ):
+    first, second = Cache( self.cache_root ), Cache( self.cache_root )
+    first.put_artifact( 'a', 1 )
+    second.put_artifact( 'b', 2 )
+    first.put_artifact( 'c', 3 )
+
+    fresh = Cache( self.cache_root )
+    self.assertEqual( [ fresh.get_artifact( key ) for key in 'abc' ], [ 1, 2, 3 ] )
+
+  def test_removal_elsewhere_is_not_undone( self ):
+    Cache( self.cache_root ).put_artifact( 'a', 1 )
+    first, second = Cache( self.cache_root ), Cache( self.cache_root )
+    second.invalidate( 'a' )
+    first.put_artifact( 'b', 2 )
+
+    fresh = Cache( self.cache_root )
+    self.assertIsNone( fresh.lookup_entry( 'a' ) )
+    self.assertEqual( fresh.get_artifact( 'b' ), 2 )
+    self.assertEqual( len( self.artifact_files() ), 1 )
+
+  def test_replacement_elsewhere_is_kept( self ):
+    Cache( self.cache_root ).put_artifact( 'a', 1 )
+    first, second = Cache( self.cache_root ), Cache( self.cache_root )
+    second.put_artifact( 'a', 2 )
+    first.put_artifact( 'b', 3 )
+
+    self.assertEqual( Cache( self.cache_root ).get_artifact( 'a' ), 2 )
+    self.assertEqual( len( self.artifact_files() ), 2 )
+
+  def test_hits_do_not_undo_a_replacement( self ):
+    Cache( self.cache_root ).put_artifact( 'a', 1 )
+    first, second = Cache( self.cache_root ), Cache( self.cache_root )
+    self.assertEqual( first.get_artifact( 'a' ), 1 )
+    second.put_artifact( 'a', 2 )
+    # served from memory, so this hit is more recent than the replacement
+    self.assertEqual( first.get_artifact( 'a' ), 1 )
+    first.flush()
+
+    fresh = Cache( self.cache_root )
+    self.assertEqual( fresh.get_artifact( 'a' ), 2 )
+    self.assertEqual( fresh.vacuum(), 0 )
+
+  def test_later_of_two_replacements_wins( self ):
+    Cache( self.cache_root ).put_artifact( 'a', 1 )
+    first, second = Cache( self.cache_root ), Cache( self.cache_root )
+    second.put_artifact( 'a', 2 )
+    first.put_artifact( 'a', 3 )
+
+    fresh = Cache( self.cache_root )
+    self.assertEqual( fresh.get_artifact( 'a' ), 3 )
+    # the losing response is removed along with the one both replaced
+    self.assertEqual( len( self.artifact_files() ), 1 )
+
+class TestHits( CacheTestCase ):
+  def test_hits_increase_across_selects( self ):
+    cache = Cache( self.cache_root )
+    cache.put_artifact( 'a', 1 )
+    metas = [ cache.get_artifact_with_meta( 'a' ) for _ in range( 3 ) ]
+    self.assertEqual( [ meta[ 'entry' ][ 'hits' ] for meta in metas ], [ 1, 2, 3 ] )
+    self.assertTrue( all( [ meta[ 'hit' ] for meta in metas ] ) )
+    self.assertFalse( cache.get_artifact_with_meta( 'b' )[ 'hit' ] )
+
+class TestPeek( CacheTestCase ):
+  def test_peek_leaves_hits_unchanged( self ):
+    cache = Cache( self.cache_root )
+    cache.put_artifact( 'a', 1 )
+    before = dict( cache.lookup_entry( 'a' ) )
+    self.assertEqual( cache.peek( 'a' ), 1 )
+    self.assertTrue( cache.contains( 'a' ) )
+    self.assertEqual( cache.lookup_entry( 'a' ), before )
+    self.assertIsNone( cache.peek( 'b' ) )
+
+class TestInvalidate( CacheTestCase ):
+  def test_lookup_misses_after_invalidate( self ):
+    cache = Cache( self.cache_root )
+    cache.put_artifacts( [ ( 'a', 1 ), ( 'b', 2 ) ] )
+    self.assertTrue( cache.invalidate( 'a' ) )
+    self.assertFalse( cache.invalidate( 'a' ) )
+
+    fresh = Cache( self.cache_root )
+    self.assertIsNone( fresh.get_artifact( 'a' ) )
+    self.assertEqual( fresh.get_artifact( 'b' ), 2 )
+    self.assertEqual( len( self.artifact_files() ), 1 )
+
+class TestFlush( CacheTestCase ):
+  def test_hits_persist_after_flush_and_reopen( self ):
+    with Cache( self.cache_root ) as cache:
+      cache.put_artifact( 'a', 1 )
+      cache.get_artifact( 'a' )
+      cache.get_artifact( 'a' )
+      # hits only reach disk when the handle is flushed
+      self.assertEqual( Cache( self.cache_root ).lookup_entry( 'a' )[ 'hits' ], 0 )
+
+    reopened = Cache( self.cache_root )
+    self.assertEqual( reopened.lookup_entry( 'a' )[ 'hits' ], 2 )
+    self.assertEqual( reopened.get_artifact( 'a' ), 1 )
+
+class TestExpiry( CacheTestCase ):
+  def backdate( self, identifier, seconds ):
+    cache = Cache( self.cache_root )
+    entry = cache.lookup_entry( identifier )
+    for key in [ 'time_first_request', 'time_last_request', 'time_stored' ]:
+      entry[ key ] -= seconds
+    cache.write_index()
+
+  def test_backdated_entry_is_a_miss( self ):
+    Cache( self.cache_root ).put_artifact( 'a', 1 )
+    self.backdate( 'a', 120 )
+
+    cache = Cache( self.cache_root )
+    self.assertIsNone( cache.get_artifact( 'a', max_age=60 ) )
+    self.assertEqual( cache.get_artifact( 'a', max_age=180 ), 1 )
+    self.assertEqual( cache.get_artifact( 'a' ), 1 )
+
+  def test_refreshed_entry_is_fresh_again( self ):
+    Cache( self.cache_root ).put_artifact( 'a', 1 )
+    self.backdate( 'a', 120 )
+
+    cache = Cache( self.cache_root )
+    cache.put_artifact( 'a', 2 )
+    self.assertEqual( cache.get_artifact( 'a', max_age=60 ), 2 )
+
+class TestMemory( CacheTestCase ):
+  def test_second_hit_skips_the_artifact_file( self ):
+    Cache( self.cache_root ).put_artifact( 'a', [ 1, 2, 3 ] )
+    cache = Cache( self.cache_root )
+    with mock.patch.object(
+        caching, 'read_artifact_contents', wraps=caching.read_artifact_contents
+    ) as read:
+      self.assertEqual( cache.get_artifact( 'a' ), [ 1, 2, 3 ] )
+      self.assertEqual( cache.get_artifact( 'a' ), [ 1, 2, 3 ] )
+    self.assertEqual( read.call_count, 1 )
+
+  def test_memory_is_capped_by_size( self ):
+    cache = Cache( self.cache_root )
+    cache.memory_bytes = 100
+    cache.put_artifacts( [ ( 'small', 'x' * 20 ), ( 'large', 'x' * 200 ) ] )
+    cache.get_artifact( 'large' )
+    self.assertEqual( len( cache.memory ), 0 )
+    for key in range( 10 ):
+      cache.put_artifact( str( key ), 'x' * 20 )
+      cache.get_artifact( str( key ) )
+    self.assertLessEqual( cache.memory_size, 100 )
+    self.assertEqual(
+      cache.memory_size, sum( [ len( contents ) for contents in cache.memory.values() ] )
+    )
+
+class TestEviction( CacheTestCase ):
+  def test_least_recently_requested_are_evicted( self ):
+    cache = Cache( self.cache_root )
+    cache.max_entries = 2
+    cache.put_artifact( 'a', 1 )
+    cache.put_artifact( 'b', 2 )
+    cache.get_artifact( 'a' )
+    cache.put_artifact( 'c', 3 )
+
+    fresh = Cache( self.cache_root )
+    identifiers = [ entry[ 'identifier' ] for entry in fresh.entries() ]
+    self.assertEqual( sorted( identifiers ), [ 'a', 'c' ] )
+
+  def test_index_is_written_once_when_nothing_is_evicted( self ):
+    cache = Cache( self.cache_root )
+    cache.max_entries = 2
+    with mock.patch.object( cache, 'write_index', wraps=cache.write_index ) as write_index:
+      cache.put_artifact( 'a', 1 )
+    self.assertEqual( write_index.call_count, 1 )
+
+class TestStreamedWrites( CacheTestCase ):
+  def test_streamed_artifact_matches_json_dumps( self ):
+    artifact = {
+      'data': [ {
+        'timestamp': key,
+        'type': 'damage',
+        'amount': key * 1.5,
+        'name': f'\u00e9v\u00e9nement {key}'
+      } for key in range( 5000 ) ]
+    }
+    path = os.path.join( self.cache_root, 'artifact.json' )
+    checksum = caching.write_artifact( artifact, path )
+
+    with open( path, 'rb' ) as handle:
+      contents = handle.read()
+    self.assertEqual( contents, json.dumps( artifact, indent=2 ).encode() )
+    self.assertEqual( checksum, hashlib.sha256( contents ).hexdigest() )
+
+class TestExport( CacheTestCase ):
+  def test_export_round_trip( self ):
+    cache = Cache( self.cache_root )
+    cache.put_artifacts( [ ( 'a', 1 ), ( 'b', {
+      'c': [ 2 ]
+    } ) ] )
+    path = os.path.join( self.cache_root, 'export.json' )
+    self.assertEqual( cache.export_json( path ), 2 )
+
+    other = Cache( os.path.join( self.cache_root, 'other' ) )
+    self.assertEqual( other.import_json( path ), 2 )
+    self.assertEqual( other.get_artifact( 'b' ), {
+      'c': [ 2 ]
+    } )
+
+  def test_failed_export_leaves_no_file( self ):
+    path = os.path.join( self.cache_root, 'export.json' )
+    with self.assertRaises( TypeError ):
+      caching.write_json_list( [ 1, object() ], path )
+    self.assertEqual( os.listdir( self.cache_root ), [] )
+
+class TestBatchRollback( CacheTestCase ):
+  def test_failed_batch_leaves_cache_unchanged( self ):
+    Cache( self.cache_root ).put_artifact( 'a', 1 )
+    cache = Cache( self.cache_root )
+    with self.assertRaises( TypeError ):
+      cache.put_artifacts( [ ( 'a', 2 ), ( 'b', 3 ), ( 'c', object() ) ] )
+
+    fresh = Cache( self.cache_root )
+    self.assertEqual( fresh.count_queries(), 1 )
+    self.assertEqual( fresh.get_artifact( 'a' ), 1 )
+    self.assertEqual( len( self.artifact_files() ), 1 )
+
+  def test_batch_is_stored( self ):
+    cache = Cache( self.cache_root )
+    self.assertEqual( cache.put_artifacts( [ ( str( key ), key ) for key in range( 100 ) ] ), 100 )
+
+    fresh = Cache( self.cache_root )
+    self.assertEqual(
+      [ fresh.get_artifact( str( key ) ) for key in range( 100 ) ], list( range( 100 ) )
+    )
+
+class SharedCacheTestCase( CacheTestCase ):
+  def setUp( self ):
+    super().setUp()
+    patcher = mock.patch.multiple( Cache, cache_root=self.cache_root, shared_instance=None )
+    patcher.start()
+    self.addCleanup( patcher.stop )
+    # flush before the cache root is removed, so the exit handler has nothing to write
+    self.addCleanup( lambda: Cache.shared_instance and Cache.flush_shared( Cache.shared_instance ) )
+    StubRequest.fetches = 0
+    StubRequest.offline = False
+
+class TestSharedFetch( SharedCacheTestCase ):
+  def test_concurrent_misses_share_one_fetch( self ):
+    request = caching.cache( StubRequest )
+    barrier = threading.Barrier( 8 )
+    results = []
+
+    def run():
+      barrier.wait()
+      results.append( request( stub_query( 'q' ) ) )
+
+    threads = [ threading.Thread( target=run ) for _ in range( 8 ) ]
+    for thread in threads:
+      thread.start()
+    for thread in threads:
+      thread.join()
+
+    self.assertEqual( StubRequest.fetches, 1 )
+    self.assertEqual( [ result.data for result in results ], [ {
+      'query': 'q',
+      'fetch': 1
+    } ] * 8 )
+    self.assertEqual( len( [ result for result in results if not result.cache_hit ] ), 1 )
+
+class TestStaleWhileRevalidate( SharedCacheTestCase ):
+  def setUp( self ):
+    super().setUp()
+    self.request = caching.cache( StubRequest )
+    self.request( stub_query( 'q' ) )
+
+  def query( self ):
+    return stub_query( 'q', max_age=60, stale_while_revalidate=True )
+
+  def expire( self ):
+    with Cache.lock:
+      Cache.shared().lookup_entry( 'q' )[ 'time_stored' ] -= 120
+
+  def test_fresh_entry_is_served( self ):
+    ret = self.request( self.query() )
+    self.assertTrue( ret.cache_hit )
+    self.assertEqual( ret.data[ 'fetch' ], 1 )
+    self.assertEqual( StubRequest.fetches, 1 )
+
+  def test_expired_entry_is_refetched( self ):
+    self.expire()
+    ret = self.request( self.query() )
+    self.assertFalse( ret.cache_hit )
+    self.assertEqual( ret.data[ 'fetch' ], 2 )
+    self.assertEqual( self.request( self.query() ).data[ 'fetch' ], 2 )
+
+  def test_expired_entry_is_served_when_network_is_down( self ):
+    self.expire()
+    StubRequest.offline = True
+    ret = self.request( self.query() )
+    self.assertTrue( ret.cache_hit )
+    self.assertEqual( ret.data[ 'fetch' ], 1 )
+
+  def test_network_failure_without_cache_is_raised( self ):
+    StubRequest.offline = True
+    with self.assertRaises( ConnectionError ):
+      self.request( stub_query( 'other', stale_while_revalidate=True ) )
+
+if __name__ == '__main__':
+  unittest.main()
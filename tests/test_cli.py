@@ -0,0 +1,67 @@
+import contextlib
+import io
+import json
+import os
+import shutil
+import tempfile
+import unittest
+
+from unittest import mock
+
+from wcl import __main__ as cli
+from wcl.caching import Cache
+
+class TestCommands( unittest.TestCase ):
+  def setUp( self ):
+    self.cache_root = tempfile.mkdtemp()
+    self.addCleanup( shutil.rmtree, self.cache_root )
+    Cache( self.cache_root ).put_artifacts( [
+      ( '{report{fights}}', [ 1 ] ),
+      ( '{report{events}}', [ 2 ] ),
+      ( '{rateLimitData{limitPerHour}}', 3 )
+    ] )
+
+  def run_command( self, *args ):
+    output = io.StringIO()
+    argv = [ 'wcl', '--cache-root', self.cache_root, *args ]
+    with mock.patch( 'sys.argv', argv ), contextlib.redirect_stdout( output ):
+      cli.main()
+    return output.getvalue()
+
+  def test_list( self ):
+    lines = self.run_command( 'list', '--width', '10' ).splitlines()
+    self.assertEqual( len( lines ), 4 )
+    self.assertTrue( all( [ len( line.split( '  ' )[ -1 ] ) <= 10 for line in lines[ 1: ] ] ) )
+
+  def test_stats( self ):
+    stats = json.loads( self.run_command( 'stats' ) )
+    self.assertEqual( stats[ 'total_queries' ], 3 )
+
+  def test_invalidate( self ):
+    self.assertEqual( self.run_command( 'invalidate', '{report' ), 'Removed 2 entries\n' )
+    self.assertEqual( Cache( self.cache_root ).count_queries(), 1 )
+
+  def test_vacuum( self ):
+    with open( os.path.join( self.cache_root, 'artifacts', 'orphan.json' ), 'w' ) as handle:
+      handle.write( '1' )
+    self.assertEqual( self.run_command( 'vacuum' ), 'Removed 1 unreferenced files\n' )
+
+  def test_export( self ):
+    path = os.path.join( self.cache_root, 'export.json' )
+    self.run_command( 'export', path )
+    with open( path ) as handle:
+      self.assertEqual( len( json.load( handle ) ), 3 )
+
+  def test_rejects_narrow_width( self ):
+    with self.assertRaises( SystemExit ), contextlib.redirect_stderr( io.StringIO() ):
+      self.run_command( 'list', '--width', '2' )
+
+  def test_rejects_missing_cache( self ):
+    missing = os.path.join( self.cache_root, 'missing' )
+    with self.assertRaises( SystemExit ), contextlib.redirect_stderr( io.StringIO() ):
+      with mock.patch( 'sys.argv', [ 'wcl', '--cache-root', missing, 'stats' ] ):
+        cli.main()
+    self.assertFalse( os.path.exists( missing ) )
+
+if __name__ == '__main__':
+  unittest.main()
@@ -0,0 +1,106 @@
+import json
+import shutil
+import tempfile
+import unittest
+
+from types import SimpleNamespace
+from unittest import mock
+
+from requests.exceptions import ConnectionError as RequestsConnectionError
+
+from wcl import query, requests
+from wcl.caching import Cache
+
+class TestRetry( unittest.TestCase ):
+  def setUp( self ):
+    patcher = mock.patch.dict( requests.retry_policy, {
+      'attempts': 3,
+      'base_delay': 0
+    } )
+    patcher.start()
+    self.addCleanup( patcher.stop )
+    self.request = object.__new__( requests.Request.__wrapped__ )
+    self.request.token = SimpleNamespace( auth='Bearer token' )
+
+  def post( self, *outcomes ):
+    with mock.patch.object( requests.requests, 'post', side_effect=outcomes ) as post:
+      try:
+        return self.request.post( 'query' )
+      finally:
+        self.calls = post.call_count
+
+  def test_retries_server_errors( self ):
+    ok = SimpleNamespace( status_code=200 )
+    self.assertIs( self.post( SimpleNamespace( status_code=503 ), ok ), ok )
+    self.assertEqual( self.calls, 2 )
+
+  def test_retries_dropped_connections( self ):
+    ok = SimpleNamespace( status_code=200 )
+    self.assertIs( self.post( RequestsConnectionError(), ok ), ok )
+    self.assertEqual( self.calls, 2 )
+
+  def test_client_errors_are_not_retried( self ):
+    bad = SimpleNamespace( status_code=400 )
+    self.assertIs( self.post( bad ), bad )
+    self.assertEqual( self.calls, 1 )
+
+  def test_gives_up_after_every_attempt( self ):
+    with self.assertRaises( SystemExit ):
+      self.post( *[ SimpleNamespace( status_code=503 ) ] * 3 )
+    self.assertEqual( self.calls, 3 )
+
+class TestGraphQLErrors( unittest.TestCase ):
+  def setUp( self ):
+    cache_root = tempfile.mkdtemp()
+    self.addCleanup( shutil.rmtree, cache_root )
+    stub_token = SimpleNamespace( auth='Bearer token', refresh_if_needed=lambda: None )
+    for patcher in [
+      mock.patch.multiple( Cache, cache_root=cache_root, shared_instance=None ),
+      mock.patch.object( requests, 'get_token', return_value=stub_token ),
+      mock.patch.object( requests.Request.__wrapped__, 'wait_for_budget' )
+    ]:
+      patcher.start()
+      self.addCleanup( patcher.stop )
+    # flush before the cache root is removed, so the exit handler has nothing to write
+    self.addCleanup( lambda: Cache.shared_instance and Cache.flush_shared( Cache.shared_instance ) )
+    self.query = query.Actors( {
+      'code': 'abc'
+    } )
+
+  def respond( self, body ):
+    response = SimpleNamespace( status_code=200, text=json.dumps( body ) )
+    with mock.patch.object( requests.requests, 'post', return_value=response ):
+      return requests.Request( self.query )
+
+  def test_errors_are_raised_and_not_cached( self ):
+    with self.assertRaises( requests.GraphQLError ) as raised:
+      self.respond( {
+        'errors': [ {
+          'message': 'Cannot query field "actor"',
+          'path': [ 'reportData', 'report' ]
+        } ]
+      } )
+    self.assertEqual( raised.exception.errors[ 0 ][ 'path' ], [ 'reportData', 'report' ] )
+    self.assertIn( 'reportData.report: Cannot query field', str( raised.exception ) )
+    self.assertIsNone( Cache.shared().lookup_entry( self.query.string ) )
+
+  def test_data_is_cached( self ):
+    actors = [ {
+      'id': 1
+    } ]
+    ret = self.respond( {
+      'data': {
+        'reportData': {
+          'report': {
+            'masterData': {
+              'actors': actors
+            }
+          }
+        }
+      }
+    } )
+    self.assertEqual( ret.data, actors )
+    self.assertEqual( Cache.shared().get_artifact( self.query.string ), actors )
+
+if __name__ == '__main__':
+  unittest.main()
@@ -0,0 +1,134 @@
+import json
+import os
+import shutil
+import tempfile
+import time
+import unittest
+
+from unittest import mock
+
+from wcl import token
+from wcl.token import Token
+
+def stored_token( access_token, lifetime=7 * 24 * 60 * 60 ):
+  return {
+    'token_type': 'Bearer',
+    'access_token': access_token,
+    'expires_at': time.time() + lifetime
+  }
+
+class TokenTestCase( unittest.TestCase ):
+  def setUp( self ):
+    self.token_root = tempfile.mkdtemp()
+    self.addCleanup( shutil.rmtree, self.token_root )
+    self.token_filename = os.path.join( self.token_root, 'token.tk' )
+    environ = {
+      key: value
+      for key, value in os.environ.items()
+      if not key.startswith( Token.token_variable )
+    }
+    for patcher in [
+      mock.patch.object( Token, 'token_filename', self.token_filename ),
+      mock.patch.dict( os.environ, environ, clear=True )
+    ]:
+      patcher.start()
+      self.addCleanup( patcher.stop )
+    # never fetch a token over the network
+    patcher = mock.patch.object( Token, 'get_token', autospec=True )
+    self.get_token = patcher.start()
+    self.addCleanup( patcher.stop )
+
+  def write_file( self, stored, filename=None ):
+    with open( filename or self.token_filename, 'w' ) as handle:
+      json.dump( stored, handle )
+
+class TestTokenSources( TokenTestCase ):
+  def test_environment_takes_precedence( self ):
+    self.write_file( stored_token( 'file' ) )
+    os.environ[ Token.token_variable ] = json.dumps( stored_token( 'env' ) )
+    self.assertEqual( Token().auth, 'Bearer env' )
+    self.get_token.assert_not_called()
+
+  def test_file_is_used_without_environment( self ):
+    self.write_file( stored_token( 'file' ) )
+    self.assertEqual( Token().auth, 'Bearer file' )
+    self.get_token.assert_not_called()
+
+  def test_incomplete_environment_token_falls_back_to_file( self ):
+    self.write_file( stored_token( 'file' ) )
+    incomplete = stored_token( 'env' )
+    del incomplete[ 'expires_at' ]
+    os.environ[ Token.token_variable ] = json.dumps( incomplete )
+    self.assertEqual( Token().auth, 'Bearer file' )
+
+  def test_new_token_is_fetched_without_either( self ):
+    Token()
+    self.get_token.assert_called_once()
+
+class TestTokenRepr( TokenTestCase ):
+  def test_repr_omits_access_token( self ):
+    self.write_file( stored_token( 'secret-access-token' ) )
+    loaded = Token()
+    self.assertNotIn( 'secret-access-token', repr( loaded ) )
+    self.assertNotIn( 'secret-access-token', f'{loaded}' )
+
+class TestRefreshPolicy( TokenTestCase ):
+  def setUp( self ):
+    super().setUp()
+    self.refresh_fn = mock.Mock( return_value=stored_token( 'refreshed' ) )
+    patcher = mock.patch.dict( token.refresh_policy, {
+      'margin': 60,
+      'refresh_fn': self.refresh_fn
+    } )
+    patcher.start()
+    self.addCleanup( patcher.stop )
+
+  def test_token_outside_margin_is_kept( self ):
+    self.write_file( stored_token( 'file', lifetime=120 ) )
+    loaded = Token()
+    loaded.refresh_if_needed()
+    self.refresh_fn.assert_not_called()
+    self.assertEqual( loaded.auth, 'Bearer file' )
+
+  def test_token_within_margin_is_refreshed( self ):
+    self.write_file( stored_token( 'file', lifetime=30 ) )
+    loaded = Token()
+    self.refresh_fn.assert_called_once_with( loaded )
+    self.assertEqual( loaded.auth, 'Bearer refreshed' )
+    self.assertEqual( Token().auth, 'Bearer refreshed' )
+
+  def test_unusable_refresh_fetches_new_token( self ):
+    self.refresh_fn.return_value = None
+    self.write_file( stored_token( 'file', lifetime=30 ) )
+    Token()
+    self.get_token.assert_called_once()
+
+  def test_missing_token_is_fetched_rather_than_refreshed( self ):
+    Token()
+    self.refresh_fn.assert_not_called()
+    self.get_token.assert_called_once()
+
+class TestNamedTokens( TokenTestCase ):
+  def test_named_tokens_are_kept_apart( self ):
+    def fetch( loaded ):
+      loaded.token = stored_token( loaded.name or 'default' )
+      loaded.load_token()
+      loaded.write_token()
+
+    self.get_token.side_effect = fetch
+    Token( 'personal' )
+    Token( 'guild' )
+    Token()
+
+    self.assertEqual( Token( 'personal' ).auth, 'Bearer personal' )
+    self.assertEqual( Token( 'guild' ).auth, 'Bearer guild' )
+    self.assertEqual( Token().auth, 'Bearer default' )
+    self.assertEqual( self.get_token.call_count, 3 )
+
+  def test_invalid_names_are_rejected( self ):
+    for name in [ 'token', 'guild-client', 'guild.client', '' ]:
+      with self.assertRaises( ValueError ):
+        Token( name )
+
+if __name__ == '__main__':
+  unittest.main()
@@ -0,0 +1,88 @@
+import argparse
+import json
+import os
+import time
+
+from .caching import Cache
+
+# cache inspection and maintenance, run as `python -m wcl <command>`.
+# none of these commands make requests or need credentials
+
+def list_entries( cache, args ):
+  print( f"{'hits':>6}  {'last request':<19}  {'uuid':<36}  query" )
+  for entry in cache.recent( args.limit ):
+    last_request = time.strftime(
+      '%Y-%m-%d %H:%M:%S', time.localtime( entry.get( 'time_last_request', 0 ) )
+    )
+    query = ' '.join( entry[ 'identifier' ].split() )
+    if len( query ) > args.width:
+      query = query[ :args.width - 3 ] + '...'
+    print( f"{entry.get( 'hits', 0 ):>6}  {last_request:<19}  {entry[ 'uuid' ]:<36}  {query}" )
+
+def print_stats( cache, args ):
+  stats = cache.stats()
+  stats.update( {
+    'size_bytes': cache.size_bytes()
+  } )
+  print( json.dumps( stats, indent=2 ) )
+
+def invalidate( cache, args ):
+  print( f'Removed {cache.invalidate_prefix( args.prefix )} entries' )
+
+def vacuum( cache, args ):
+  print( f'Removed {cache.vacuum()} unreferenced files' )
+
+def export( cache, args ):
+  print( f'Exported {cache.export_json( args.path )} entries to {args.path}' )
+
+def query_width( value ):
+  # room for at least one character of the query before the ellipsis
+  width = int( value )
+  if width < 4:
+    raise argparse.ArgumentTypeError( f'width must be at least 4, not {width}' )
+  return width
+
+def main():
+  parser = argparse.ArgumentParser(
+    prog='python -m wcl', description='Inspect and manage the response cache'
+  )
+  parser.add_argument(
+    '--cache-root',
+    default=None,
+    help=f'cache directory (default: {Cache.cache_root})'
+  )
+  commands = parser.add_subparsers( dest='command', required=True )
+
+  list_parser = commands.add_parser( 'list', help='most recently requested entries' )
+  list_parser.add_argument( '--limit', type=int, default=20 )
+  list_parser.add_argument(
+    '--width', type=query_width, default=60, help='truncate queries to this width'
+  )
+  list_parser.set_defaults( run=list_entries )
+
+  stats_parser = commands.add_parser( 'stats', help='cache statistics as JSON' )
+  stats_parser.set_defaults( run=print_stats )
+
+  invalidate_parser = commands.add_parser(
+    'invalidate', help='drop every entry whose query starts with a prefix'
+  )
+  invalidate_parser.add_argument( 'prefix' )
+  invalidate_parser.set_defaults( run=invalidate )
+
+  vacuum_parser = commands.add_parser( 'vacuum', help='delete files no entry refers to' )
+  vacuum_parser.set_defaults( run=vacuum )
+
+  export_parser = commands.add_parser( 'export', help='write every cached response to a file' )
+  export_parser.add_argument( 'path' )
+  export_parser.set_defaults( run=export )
+
+  args = parser.parse_args()
+  # Cache() would create an empty cache at a mistyped path rather than report it
+  cache_root = os.path.expanduser( args.cache_root or Cache.cache_root )
+  if not os.path.isfile( os.path.join( cache_root, 'index.json' ) ):
+    parser.error( f'{cache_root} is not a cache' )
+  with Cache.lock:
+    args.run( Cache( args.cache_root ), args )
+
+if __name__ == '__main__':
+  main()
@@ -1,83 +1,839 @@
+import atexit
+import hashlib
 import json
+import logging
 import os
+import threading
+import time
 import uuid
 
-# Caches queries by identifier in a lookup table in `repo root/cache`.
+try:
+  import fcntl
+except ImportError:
+  fcntl = None
+
+from collections import OrderedDict
+from contextlib import contextmanager
+
+log = logging.getLogger( __name__ )
+
+# Caches queries by identifier in a lookup table in `Cache.cache_root`.
 # As implemented in requests, identifier is completed query that has been
 # stringified and is ready to be passed as a Request.
 
+cache_policies = [ 'cache_first', 'network_first', 'cache_only', 'network_only' ]
+
+class CacheMiss( LookupError ):
+  pass
+
 def cache( Request ):
-  def decorator( query ):
-    ret = None
-    cache = Cache()
-
-    if query.cacheable:
-      ret = Request( query, cache.get_artifact( query.string ) )
-    if ret is None:
-      ret = Request( query )
-    if query.cacheable and not cache.lookup_uuid( query.string ):
-      cache.put_artifact( query.string, ret.data )
+  # how a query uses the cache follows its `cache_policy`, one of cache_policies.
+  # queries with `stale_while_revalidate` still refetch expired entries, but are
+  # served the expired copy if that request fails
+  def lookup( query ):
+    # the shared handle is only touched under Cache.lock, which is released for
+    # the request itself so misses for different queries still fetch in parallel
+    stale = None
+    with Cache.lock:
+      data = Cache.shared().get_artifact( query.string, query.max_age )
+      if data is None and query.stale_while_revalidate:
+        stale = Cache.shared().peek( query.string )
+    log.debug( 'cache %s for %s', 'miss' if data is None else 'hit', query.string )
+    return data, stale
+
+  def fetch( query, data, stale, store ):
+    try:
+      ret = Request( query, data )
+    except Request.failures:
+      # fall back to the cached copy if there is one
+      if stale is None:
+        raise
+      log.warning( 'Refetch failed, serving stale cache for %s', query.string )
+      ret = Request( query, stale )
+      ret.cache_hit = True
+      return ret
+    ret.cache_hit = data is not None
+    if store and data is None:
+      with Cache.lock:
+        Cache.shared().put_artifact( query.string, ret.data )
     return ret
 
+  def decorator( query ):
+    assert query.cache_policy in cache_policies, f'{query.cache_policy} is not a cache policy'
+    if not query.cacheable or query.cache_policy == 'network_only':
+      return fetch( query, None, None, False )
+    if query.cache_policy == 'network_first':
+      with Cache.lock:
+        stale = Cache.shared().peek( query.string )
+      return fetch( query, None, stale, True )
+    data, stale = lookup( query )
+    if data is not None:
+      return fetch( query, data, None, False )
+    if query.cache_policy == 'cache_only':
+      raise CacheMiss( query.string )
+    # concurrent misses for the same query wait for the first to fetch and store it,
+    # then look again, so the query is only requested once
+    with Cache.in_flight( query.string ):
+      data, stale = lookup( query )
+      return fetch( query, data, stale, True )
+
+  decorator.__wrapped__ = Request
   return decorator
 
+def probe( query ):
+  # reports whether `query` would be served from cache without requesting it
+  assert query.cache_policy in cache_policies, f'{query.cache_policy} is not a cache policy'
+  if not query.cacheable or query.cache_policy == 'network_only':
+    return {
+      'hit': False
+    }
+  with Cache.lock:
+    return Cache.shared().probe( query.string, query.max_age )
+
+def invalidate( query ):
+  # drops the cached response for `query`, reporting whether there was one
+  with Cache.lock:
+    return Cache.shared().invalidate( query.string )
+
 class Cache:
-  cache_root = 'cache'
-  cache_index = cache_root + '/index.json'
-  cache_artifacts = cache_root + '/artifacts/'
+  # `$CRONCH_CACHE_PATH` or `cache` in the working directory. set this (or pass
+  # `cache_root` to Cache) to share one cache between tools run from elsewhere
+  cache_root = os.environ.get( 'CRONCH_CACHE_PATH', 'cache' )
+  max_entries = None
+  # total size of the artifact file contents each handle keeps in memory
+  memory_bytes = 32 * 1024 * 1024
+  shared_instance = None
+  lock = threading.RLock()
+  flights = {}
 
-  def __init__( self ):
-    self.cache = read_artifact( self.cache_index )
-    if self.cache is None:
+  @classmethod
+  def shared( cls ):
+    # one handle per cache root so the index is not re-read for every request.
+    # handles are not thread safe: hold Cache.lock while using the shared one
+    cache_root = os.path.expanduser( cls.cache_root )
+    if cls.shared_instance is None or cls.shared_instance.cache_root != cache_root:
+      if cls.shared_instance is not None:
+        cls.shared_instance.flush()
+      cls.shared_instance = cls()
+      atexit.register( cls.flush_shared, cls.shared_instance )
+    return cls.shared_instance
+
+  @classmethod
+  @contextmanager
+  def in_flight( cls, identifier ):
+    # one lock per identifier being fetched, dropped once nobody is waiting on it
+    with cls.lock:
+      flight = cls.flights.setdefault( identifier, {
+        'lock': threading.Lock(),
+        'waiters': 0
+      } )
+      flight[ 'waiters' ] += 1
+    try:
+      with flight[ 'lock' ]:
+        yield
+    finally:
+      with cls.lock:
+        flight[ 'waiters' ] -= 1
+        if not flight[ 'waiters' ]:
+          del cls.flights[ identifier ]
+
+  @classmethod
+  def flush_shared( cls, instance ):
+    with cls.lock:
+      instance.flush()
+
+  def __init__( self, cache_root=None ):
+    self.cache_root = os.path.expanduser( cache_root or self.cache_root )
+    self.cache_index = os.path.join( self.cache_root, 'index.json' )
+    self.cache_artifacts = os.path.join( self.cache_root, 'artifacts' )
+
+    if not os.path.isfile( self.cache_index ):
       self.initialize_cache()
-      self.cache = read_artifact( self.cache_index )
-    assert ( self.cache is not None )
+    self.cache = read_index( self.cache_index )
+    # entries by hash, so lookups do not scan the index
+    self.entries_by_hash = { entry[ 'hash' ]: entry for entry in self.cache }
+    self.memory = OrderedDict()
+    self.memory_size = 0
+    self.dirty = False
+    self.discarded = []
+    self.mark_synced()
 
   def initialize_cache( self ):
     init = []
     write_artifact( init, self.cache_index )
 
-  def get_artifact( self, identifier ):
+  def get_artifact( self, identifier, max_age=None ):
+    return self.get_artifact_with_meta( identifier, max_age ).get( 'artifact' )
+
+  def get_artifact_with_meta( self, identifier, max_age=None ):
+    # artifact plus a copy of its index entry and whether this lookup was a hit.
+    # recently read artifacts are kept in memory so repeated hits skip the file, but
+    # every hit parses its own copy, so callers may modify what they are given. hits
+    # update the entry and only reach disk with the next index write or `flush`.
+    # artifacts that no longer match their `checksum` are dropped and refetched
     self.artifact = None
-    self.uuid = self.lookup_uuid( identifier )
+    self.entry = self.lookup_entry( identifier )
 
-    if self.uuid is None:
+    if self.entry is None:
       self.uuid = uuid.uuid4()
+    else:
+      self.uuid = self.entry[ 'uuid' ]
     self.path = self.generate_path()
-    self.artifact = read_artifact( self.path )
 
-    return self.artifact
+    if self.entry is not None and not self.expired( self.entry, max_age ):
+      if self.entry[ 'hash' ] in self.memory:
+        self.memory.move_to_end( self.entry[ 'hash' ] )
+        self.artifact = json.loads( self.memory[ self.entry[ 'hash' ] ] )
+        self.record_hit( self.entry, persist=False )
+      elif not os.path.isfile( self.path ) and self.index_changed():
+        # another process may have replaced the artifact; look again with its entry
+        self.merge_index()
+        return self.get_artifact_with_meta( identifier, max_age )
+      else:
+        contents = None
+        try:
+          contents = read_artifact_contents( self.path, self.entry.get( 'checksum' ) )
+        except CorruptArtifact:
+          log.warning( 'Cache artifact %s does not match its checksum', self.entry[ 'uuid' ] )
+        if contents is not None:
+          self.artifact = json.loads( contents )
+          self.remember( self.entry[ 'hash' ], contents )
+          self.record_hit( self.entry, persist=False )
+        else:
+          # the artifact was deleted or corrupted out from under its entry; drop the
+          # entry so the refetched response is stored as new rather than as a hit
+          log.warning( 'Dropping cache entry %s with unreadable artifact', self.entry[ 'uuid' ] )
+          self.remove_entries( [ self.entry ] )
+          self.entry = None
+
+    return {
+      'artifact': self.artifact,
+      'entry': dict( self.entry ) if self.entry is not None else None,
+      'hit': self.artifact is not None
+    }
+
+  def probe( self, identifier, max_age=None ):
+    # unlike get_artifact, this neither reads the artifact nor records a hit
+    entry = self.lookup_entry( identifier )
+    if entry is None or self.expired( entry, max_age ):
+      return {
+        'hit': False
+      }
+    if not os.path.isfile( self.artifact_path( entry[ 'uuid' ] ) ):
+      return {
+        'hit': False
+      }
+    return {
+      'hit': True,
+      'hits': entry.get( 'hits', 0 ),
+      'age': time.time() - entry.get( 'time_stored', 0 )
+    }
+
+  def contains( self, identifier, max_age=None ):
+    return self.probe( identifier, max_age )[ 'hit' ]
+
+  def peek( self, identifier, max_age=None ):
+    # the cached artifact, without recording a hit or populating memory
+    if not self.contains( identifier, max_age ):
+      return None
+    entry = self.lookup_entry( identifier )
+    if entry[ 'hash' ] in self.memory: # type: ignore
+      return json.loads( self.memory[ entry[ 'hash' ] ] ) # type: ignore
+    try:
+      return read_artifact(
+        self.artifact_path( entry[ 'uuid' ] ), entry.get( 'checksum' ) # type: ignore
+      )
+    except CorruptArtifact:
+      return None
 
   def put_artifact( self, identifier, data ):
+    # the response is written to a new file that the entry only points at once the
+    # index is written, so the indexed file always matches the indexed checksum
     self.data = data
-    payload = {
-      'identifier': identifier,
-      'uuid': str( self.uuid )
+    self.uuid, checksum = self.stage_artifact( data )
+    self.path = self.generate_path()
+    entry = self.lookup_entry( identifier )
+
+    if entry is None:
+      entry = new_entry( identifier, self.uuid )
+      entry[ 'checksum' ] = checksum
+      self.add_entry( entry )
+      self.write_index()
+      if self.max_entries is not None:
+        self.evict_lru( self.max_entries )
+    else:
+      replaced = entry[ 'uuid' ]
+      entry.update( {
+        'uuid': self.uuid,
+        'checksum': checksum,
+        'time_stored': time.time()
+      } )
+      self.forget( entry[ 'hash' ] )
+      # refreshing an expired entry still counts as a hit
+      self.record_hit( entry )
+      remove_artifact( self.artifact_path( replaced ) )
+
+  def put_artifacts( self, artifacts ):
+    # stores (identifier, data) pairs. every response is written to a new file
+    # first; if any fails, those files are removed and the cache is unchanged.
+    # entries then point at the new files, the index is written once and only
+    # then are the files they replaced removed
+    staged = {}
+    try:
+      for identifier, data in artifacts:
+        if identifier in staged:
+          remove_artifact( self.artifact_path( staged[ identifier ][ 0 ] ) )
+        staged[ identifier ] = self.stage_artifact( data )
+    except BaseException:
+      for artifact_uuid, _ in staged.values():
+        remove_artifact( self.artifact_path( artifact_uuid ) )
+      raise
+
+    replaced = []
+    for identifier, ( artifact_uuid, checksum ) in staged.items():
+      entry = self.lookup_entry( identifier )
+      if entry is None:
+        entry = new_entry( identifier, artifact_uuid )
+        self.add_entry( entry )
+      else:
+        replaced.append( entry[ 'uuid' ] )
+      entry.update( {
+        'uuid': artifact_uuid,
+        'checksum': checksum,
+        'time_stored': time.time(),
+        'time_last_request': time.time()
+      } )
+      self.forget( entry[ 'hash' ] )
+    self.write_index()
+    for artifact_uuid in replaced:
+      remove_artifact( self.artifact_path( artifact_uuid ) )
+
+    if self.max_entries is not None:
+      self.evict_lru( self.max_entries )
+    return len( staged )
+
+  def stage_artifact( self, data ):
+    # writes `data` under a fresh uuid, returning the uuid and the file's checksum
+    artifact_uuid = str( uuid.uuid4() )
+    return artifact_uuid, write_artifact( data, self.artifact_path( artifact_uuid ) )
+
+  def record_hit( self, entry, persist=True ):
+    entry.update( {
+      'hits': entry.get( 'hits', 0 ) + 1,
+      'time_last_request': time.time()
+    } )
+    if persist:
+      self.write_index()
+    else:
+      self.dirty = True
+
+  def write_index( self ):
+    # other processes may share this cache root, so their changes are merged in first
+    with index_lock( self.cache_root ):
+      self.merge_index()
+      write_artifact( self.cache, self.cache_index )
+      self.dirty = False
+      self.mark_synced()
+    # responses that lost a merge are only removed once no index refers to them
+    for artifact_uuid in self.discarded:
+      remove_artifact( self.artifact_path( artifact_uuid ) )
+    self.discarded = []
+
+  def index_changed( self ):
+    return file_state( self.cache_index ) != self.index_state
+
+  def mark_synced( self ):
+    # what the index file held when this handle last read or wrote it
+    self.index_state = file_state( self.cache_index )
+    self.synced = { entry[ 'hash' ]: dict( entry ) for entry in self.cache }
+
+  def merge_index( self ):
+    # another process sharing this cache root may have written the index since this
+    # handle last synced. its additions, changes and removals are kept, with this
+    # handle's own changes applied on top. when both changed an entry, the more
+    # recently stored response wins, keeping the higher hit count and latest request
+    state = file_state( self.cache_index )
+    if state == self.index_state:
+      return
+    on_disk = read_index( self.cache_index )
+    baseline = { entry[ 'hash' ]: dict( entry ) for entry in on_disk }
+    merged = OrderedDict( [ ( entry[ 'hash' ], entry ) for entry in on_disk ] )
+    for digest, synced in self.synced.items():
+      ours = self.entries_by_hash.get( digest )
+      theirs = merged.get( digest )
+      if ours is None:
+        # removed here, along with any response stored for it elsewhere meanwhile
+        merged.pop( digest, None )
+        if theirs is not None and not same_artifact( theirs, synced ):
+          self.discarded.append( theirs[ 'uuid' ] )
+      elif ours == synced:
+        # unchanged here, so whatever the other process did stands
+        if theirs != synced:
+          self.forget( digest )
+      elif theirs is None:
+        # removed elsewhere: that stands unless a new response was stored here
+        if not same_artifact( ours, synced ):
+          merged[ digest ] = ours
+      elif theirs == synced:
+        merged[ digest ] = ours
+      else:
+        latest, other = sorted( [ ours, theirs ], key=store_rank, reverse=True )
+        latest.update( {
+          'hits': max( ours.get( 'hits', 0 ), theirs.get( 'hits', 0 ) ),
+          'time_last_request': max(
+            ours.get( 'time_last_request', 0 ), theirs.get( 'time_last_request', 0 )
+          )
+        } )
+        merged[ digest ] = latest
+        self.forget( digest )
+        if not same_artifact( other, synced ) and other[ 'uuid' ] != latest[ 'uuid' ]:
+          self.discarded.append( other[ 'uuid' ] )
+    for digest, ours in self.entries_by_hash.items():
+      if digest not in self.synced:
+        # added here
+        merged[ digest ] = ours
+
+    self.cache = list( merged.values() )
+    self.entries_by_hash = dict( merged )
+    # what remains different from the file is what this handle changed
+    self.index_state = state
+    self.synced = baseline
+
+  def flush( self ):
+    # writes hits recorded only in memory, merged into the index as it is on disk.
+    # handles flush when used as a context manager, and the shared handle when it
+    # is replaced or the interpreter exits
+    if self.dirty:
+      self.write_index()
+
+  def __enter__( self ):
+    return self
+
+  def __exit__( self, *exc ):
+    self.flush()
+
+  def remember( self, digest, contents ):
+    # artifacts larger than the whole capacity are never kept
+    self.forget( digest )
+    if len( contents ) > self.memory_bytes:
+      return
+    self.memory[ digest ] = contents
+    self.memory_size += len( contents )
+    while self.memory_size > self.memory_bytes:
+      _, dropped = self.memory.popitem( last=False )
+      self.memory_size -= len( dropped )
+
+  def forget( self, digest ):
+    self.memory_size -= len( self.memory.pop( digest, b'' ) )
+
+  def clear_memory( self ):
+    self.memory.clear()
+    self.memory_size = 0
+
+  def expired( self, entry, max_age ):
+    # age is measured from `time_stored`, when the response was last fetched, so
+    # neither hits nor an old first request keep a refreshed response from expiring
+    # or becoming fresh again. a max_age of 0 always refetches, None never expires
+    if max_age is None:
+      return False
+    return time.time() - entry.get( 'time_stored', 0 ) >= max_age
+
+  def purge_expired( self, max_age ):
+    # drops every entry older than max_age along with its artifact
+    return self.remove_entries( [
+      entry for entry in self.cache if self.expired( entry, max_age )
+    ] )
+
+  def invalidate_prefix( self, prefix ):
+    return self.remove_entries( [
+      entry
+      for entry in self.cache
+      if entry.get( 'identifier', '' ).startswith( prefix )
+    ] )
+
+  def invalidate( self, identifier ):
+    # drops the cached response for exactly this query, so it is refetched next time
+    entry = self.lookup_entry( identifier )
+    if entry is None:
+      return False
+    self.remove_entries( [ entry ] )
+    return True
+
+  def delete( self, artifact_uuid ):
+    # removes the entry stored under `artifact_uuid` along with its artifact
+    return self.remove_entries( [
+      entry for entry in self.cache if entry[ 'uuid' ] == str( artifact_uuid )
+    ] )
+
+  def invalidate_all( self ):
+    return self.remove_entries( list( self.cache ) )
+
+  def evict_lru( self, keep ):
+    # drops the least recently requested entries until at most `keep` remain
+    return self.prune( 'lru', max_entries=keep )
+
+  def prune( self, policy='lru', max_entries=None, max_bytes=None, vacuum=False ):
+    # 'lru' keeps the most recently requested entries, 'lfu' the most hit.
+    # entries are dropped until both max_entries and max_bytes are satisfied
+    orderings = {
+      'lru': lambda entry: entry.get( 'time_last_request', 0 ),
+      'lfu': lambda entry: ( entry.get( 'hits', 0 ), entry.get( 'time_last_request', 0 ) )
+    }
+    assert policy in orderings, f'{policy} is not a prune policy'
+
+    survivors = sorted( self.cache, key=orderings[ policy ], reverse=True )
+    if max_entries is not None:
+      survivors = survivors[ :max_entries ]
+    if max_bytes is not None:
+      total_bytes = 0
+      for count, entry in enumerate( survivors ):
+        total_bytes += self.artifact_size( entry )
+        if total_bytes > max_bytes:
+          survivors = survivors[ :count ]
+          break
+
+    kept = set( [ id( entry ) for entry in survivors ] )
+    removed = self.remove_entries( [
+      entry for entry in self.cache if id( entry ) not in kept
+    ] )
+    if vacuum:
+      self.vacuum()
+    return removed
+
+  def remove_entries( self, entries ):
+    # rewrite the index once, before touching artifacts, so partial removals are never visible
+    if not entries:
+      return 0
+    removed = set( [ entry[ 'hash' ] for entry in entries ] )
+    self.cache = [ entry for entry in self.cache if entry[ 'hash' ] not in removed ]
+    for digest in removed:
+      self.entries_by_hash.pop( digest, None )
+      self.forget( digest )
+    self.write_index()
+    for entry in entries:
+      remove_artifact( self.artifact_path( entry[ 'uuid' ] ) )
+    return len( entries )
+
+  def verify_integrity( self ):
+    # uuids of entries whose artifact is missing or no longer matches its checksum.
+    # entries written before checksums were stored are only checked for existence
+    failed = []
+    for entry in self.cache:
+      path = self.artifact_path( entry[ 'uuid' ] )
+      if not os.path.isfile( path ):
+        failed.append( entry[ 'uuid' ] )
+      elif 'checksum' in entry and file_checksum( path ) != entry[ 'checksum' ]:
+        failed.append( entry[ 'uuid' ] )
+    return failed
+
+  def entries( self ):
+    # copies, so callers inspecting the cache cannot corrupt the index
+    return [ dict( entry ) for entry in self.cache ]
+
+  def iter_artifacts( self ):
+    # yields ( entry, artifact ) pairs, reading each artifact only when it is reached.
+    # entries are copies taken up front; missing or corrupt artifacts are skipped
+    for entry in self.entries():
+      try:
+        artifact = read_artifact( self.artifact_path( entry[ 'uuid' ] ), entry.get( 'checksum' ) )
+      except ( CorruptArtifact, ValueError ):
+        # artifacts from before checksums were stored can only be caught failing to parse
+        log.warning( 'Skipping corrupt cache artifact %s', entry[ 'uuid' ] )
+        continue
+      if artifact is not None:
+        yield entry, artifact
+
+  def stats( self ):
+    first_requests = [
+      entry[ 'time_first_request' ]
+      for entry in self.cache
+      if 'time_first_request' in entry
+    ]
+    return {
+      'total_queries': len( self.cache ),
+      'total_hits': sum( [ entry.get( 'hits', 0 ) for entry in self.cache ] ),
+      'total_misses': len( [
+        entry for entry in self.cache if not entry.get( 'hits', 0 )
+      ] ),
+      'artifact_bytes': self.artifact_bytes(),
+      'oldest_first_request': min( first_requests, default=None ),
+      'newest_first_request': max( first_requests, default=None )
     }
 
-    self.cache.append( payload ) # type: ignore
-    write_artifact( self.cache, self.cache_index )
-    write_artifact( self.data, self.path )
+  def count_queries( self ):
+    return len( self.cache )
+
+  def count_responses( self ):
+    # entries whose artifact is still on disk
+    return len( [
+      entry for entry in self.cache
+      if os.path.isfile( self.artifact_path( entry[ 'uuid' ] ) )
+    ] )
+
+  def artifact_bytes( self ):
+    return sum( [ self.artifact_size( entry ) for entry in self.cache ] )
+
+  def size_bytes( self ):
+    # on-disk size of the index and every stored artifact
+    index_bytes = os.path.getsize( self.cache_index ) if os.path.isfile( self.cache_index ) else 0
+    return index_bytes + self.artifact_bytes()
+
+  def artifact_size( self, entry ):
+    path = self.artifact_path( entry[ 'uuid' ] )
+    return os.path.getsize( path ) if os.path.isfile( path ) else 0
+
+  def vacuum( self ):
+    # deletes artifact and temporary files no index entry refers to, such as
+    # those left by interrupted writes. the index as other handles last wrote it is
+    # merged in first, so their entries are kept. meant for maintenance, not while
+    # another process is writing to the same cache
+    if not os.path.isdir( self.cache_artifacts ):
+      return 0
+    with index_lock( self.cache_root ):
+      self.merge_index()
+      referenced = set( [
+        os.path.basename( self.artifact_path( entry[ 'uuid' ] ) )
+        for entry in self.cache
+      ] )
+      orphaned = [
+        os.path.join( self.cache_artifacts, name )
+        for name in os.listdir( self.cache_artifacts )
+        if name not in referenced
+      ]
+      for path in orphaned:
+        remove_artifact( path )
+    return len( orphaned )
+
+  def export_json( self, path ):
+    # every cached response with its query and hit count, for inspection or import_json.
+    # responses are streamed to the file one at a time; missing or corrupt ones are skipped
+    return write_json_list( (
+      {
+        'query': entry[ 'identifier' ],
+        'hits': entry.get( 'hits', 0 ),
+        'response': artifact
+      } for entry, artifact in self.iter_artifacts()
+    ), path )
+
+  def import_json( self, path ):
+    # queries that are already cached are skipped; imported entries keep their hits
+    exported = read_artifact( path )
+    assert isinstance( exported, list ), f'{path} is not a cache export'
+    fresh = [ item for item in exported if self.lookup_entry( item[ 'query' ] ) is None ]
+    imported = self.put_artifacts( [ ( item[ 'query' ], item[ 'response' ] ) for item in fresh ] )
+
+    for item in fresh:
+      entry = self.lookup_entry( item[ 'query' ] )
+      if entry is not None:
+        entry[ 'hits' ] = item.get( 'hits', 0 )
+    self.write_index()
+    return imported
+
+  def merge_from( self, cache_root ):
+    # copies entries from the cache at `cache_root` into this one. when both hold a
+    # query, the entry with more hits wins, then the more recently requested one.
+    # artifacts are written to new files before the index, as in put_artifacts
+    other_index = os.path.join( os.path.expanduser( cache_root ), 'index.json' )
+    if not os.path.isfile( other_index ):
+      # Cache() would create an empty cache there and merge nothing
+      raise FileNotFoundError( f'{other_index} does not exist, {cache_root} is not a cache' )
+    other = Cache( cache_root )
+    merged = []
+    for theirs in other.cache:
+      ours = self.lookup_entry( theirs[ 'identifier' ] )
+      if ours is not None and merge_rank( ours ) >= merge_rank( theirs ):
+        continue
+      try:
+        data = read_artifact( other.artifact_path( theirs[ 'uuid' ] ), theirs.get( 'checksum' ) )
+      except CorruptArtifact:
+        data = None
+      if data is None:
+        log.warning( 'Skipping unreadable artifact %s in %s', theirs[ 'uuid' ], cache_root )
+        continue
+
+      entry = dict( theirs )
+      entry[ 'uuid' ], entry[ 'checksum' ] = self.stage_artifact( data )
+      self.forget( entry[ 'hash' ] )
+      merged.append( ( ours, entry ) )
+
+    replaced = []
+    for ours, entry in merged:
+      if ours is None:
+        self.add_entry( entry )
+      else:
+        replaced.append( ours[ 'uuid' ] )
+        ours.update( entry )
+    self.write_index()
+    for artifact_uuid in replaced:
+      remove_artifact( self.artifact_path( artifact_uuid ) )
+
+    if self.max_entries is not None:
+      self.evict_lru( self.max_entries )
+    return len( merged )
+
+  def recent( self, limit ):
+    # the `limit` most recently requested entries, newest first
+    return sorted(
+      self.entries(),
+      key=lambda entry: entry.get( 'time_last_request', 0 ),
+      reverse=True
+    )[ :max( limit, 0 ) ]
+
+  def add_entry( self, entry ):
+    self.cache.append( entry )
+    self.entries_by_hash[ entry[ 'hash' ] ] = entry
+
+  def lookup_entry( self, identifier ):
+    return self.entries_by_hash.get( identifier_hash( identifier ) )
 
   def lookup_uuid( self, identifier ):
-    for entry in self.cache: # type: ignore
-      if entry.get( 'identifier' ) == identifier:
-        return entry[ 'uuid' ]
-    return None
+    entry = self.lookup_entry( identifier )
+    return entry[ 'uuid' ] if entry is not None else None
 
   def generate_path( self ):
-    return self.cache_artifacts + str( self.uuid ) + '.json'
+    return self.artifact_path( self.uuid )
+
+  def artifact_path( self, artifact_uuid ):
+    return os.path.join( self.cache_artifacts, str( artifact_uuid ) + '.json' )
+
+def read_index( path ):
+  index = read_artifact( path )
+  if not isinstance( index, list ) or not all( [
+      isinstance( entry, dict ) and 'identifier' in entry and 'uuid' in entry
+      for entry in index
+  ] ):
+    raise ValueError( f'{path} is not a cache index' )
+  return unique_entries( index )
+
+def same_artifact( entry, other ):
+  return entry[ 'uuid' ] == other[ 'uuid' ] and entry.get( 'checksum' ) == other.get( 'checksum' )
 
-def read_artifact( path ):
+@contextmanager
+def index_lock( cache_root ):
+  # serializes index writes between processes sharing `cache_root`. where fcntl is
+  # unavailable (windows) writes are unlocked and simultaneous ones can still race
+  if fcntl is None:
+    yield
+    return
+  os.makedirs( cache_root, exist_ok=True )
+  with open( os.path.join( cache_root, 'index.lock' ), 'a' ) as handle:
+    fcntl.flock( handle, fcntl.LOCK_EX )
+    try:
+      yield
+    finally:
+      fcntl.flock( handle, fcntl.LOCK_UN )
+
+def file_state( path ):
+  # changes whenever the file is replaced, so other writers can be noticed cheaply
   if not os.path.isfile( path ):
     return None
-  with open( path, 'r' ) as handle:
-    json_object = json.load( handle )
-  return json_object
+  stat = os.stat( path )
+  return ( stat.st_mtime_ns, stat.st_size, stat.st_ino )
+
+def unique_entries( entries ):
+  # identifiers are unique: keep the first entry for each, as lookup_entry would
+  seen = set()
+  unique = []
+  for entry in entries:
+    if entry[ 'identifier' ] not in seen:
+      seen.add( entry[ 'identifier' ] )
+      # indexes written before entries were hashed or stored their fetch time
+      entry.setdefault( 'hash', identifier_hash( entry[ 'identifier' ] ) )
+      entry.setdefault( 'time_stored', entry.get( 'time_first_request', 0 ) )
+      unique.append( entry )
+  return unique
+
+def store_rank( entry ):
+  # hits alone never replace a response stored meanwhile by another handle
+  return ( entry.get( 'time_stored', 0 ), entry.get( 'time_last_request', 0 ) )
+
+def merge_rank( entry ):
+  return ( entry.get( 'hits', 0 ), entry.get( 'time_last_request', 0 ) )
+
+def identifier_hash( identifier ):
+  return hashlib.sha256( identifier.encode() ).hexdigest()
+
+def new_entry( identifier, artifact_uuid ):
+  return {
+    'identifier': identifier,
+    'hash': identifier_hash( identifier ),
+    'uuid': str( artifact_uuid ),
+    'hits': 0,
+    'time_first_request': time.time(),
+    'time_last_request': time.time(),
+    'time_stored': time.time()
+  }
+
+class CorruptArtifact( Exception ):
+  pass
+
+def read_artifact( path, checksum=None ):
+  # raises CorruptArtifact if `checksum` is given and the file no longer matches it
+  contents = read_artifact_contents( path, checksum )
+  return json.loads( contents ) if contents is not None else None
+
+def read_artifact_contents( path, checksum=None ):
+  # the raw file contents, checked as in read_artifact but not parsed
+  if not os.path.isfile( path ):
+    return None
+  with open( path, 'rb' ) as handle:
+    contents = handle.read()
+  if checksum is not None and hashlib.sha256( contents ).hexdigest() != checksum:
+    raise CorruptArtifact( path )
+  return contents
+
+class ChecksumWriter:
+  # passes text through to a binary handle as UTF-8, hashing exactly the bytes written
+  def __init__( self, handle ):
+    self.handle = handle
+    self.digest = hashlib.sha256()
+
+  def write( self, text ):
+    data = text.encode()
+    self.digest.update( data )
+    self.handle.write( data )
+
+@contextmanager
+def replacing( path, mode ):
+  # yields a handle to a file beside `path` that is renamed over it once the block
+  # completes, so readers never see a partial file. on failure it is removed instead
+  if os.path.dirname( path ):
+    os.makedirs( os.path.dirname( path ), exist_ok=True )
+  temp_path = path + '.' + str( uuid.uuid4() ) + '.tmp'
+  try:
+    with open( temp_path, mode ) as handle:
+      yield handle
+  except BaseException:
+    remove_artifact( temp_path )
+    raise
+  os.replace( temp_path, path )
 
 def write_artifact( artifact, path ):
-  json_object = json.dumps( artifact, indent=2 )
-  os.makedirs( os.path.dirname( path ), exist_ok=True )
-  with open( path, "w" ) as handle:
-    handle.write( json_object )
+  # returns the checksum of the written file. the artifact is encoded straight into
+  # the file, producing the same bytes as json.dumps without holding them all at once
+  with replacing( path, "wb" ) as handle:
+    writer = ChecksumWriter( handle )
+    json.dump( artifact, writer, indent=2 )
+  return writer.digest.hexdigest()
+
+def write_json_list( items, path ):
+  # writes an iterable as a JSON list, encoding one item at a time, and returns how many
+  # items were written. like write_artifact, the file is replaced only once complete
+  count = 0
+  with replacing( path, "w" ) as handle:
+    handle.write( '[' )
+    for item in items:
+      handle.write( ',\n' if count else '\n' )
+      json.dump( item, handle, indent=2 )
+      count += 1
+    handle.write( '\n]' if count else ']' )
+  return count
+
+def file_checksum( path ):
+  with open( path, 'rb' ) as handle:
+    return hashlib.sha256( handle.read() ).hexdigest()
+
+def remove_artifact( path ):
+  if os.path.isfile( path ):
+    os.remove( path )
   return 0
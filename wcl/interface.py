@@ -4,7 +4,7 @@ from copy import deepcopy
 from numpy import char
 
 from . import query
-from .requests import Request
+from .requests import Request, rate_limit_status
 
 def getFights( params ):
   return Request( query.Fights( params ) ).data
@@ -57,8 +57,7 @@ def printPlayerDetails( params ):
       print( json.dumps( player, indent=2 ) )
 
 def getPointsSpent():
-  params = {}
-  req = Request( query.PointsSpentThisHour( params ) ).data.get( 'pointsSpentThisHour' )
+  req = rate_limit_status().get( 'pointsSpentThisHour' )
   return print( req, 'point' if req == 1 else 'points', 'spent this hour' ) # pyright: ignore
 
 def getPlayerFromID( id, params ):
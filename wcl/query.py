@@ -2,6 +2,10 @@ class Query:
   params = {}
   parent = None
   cacheable = True
+  max_age = None
+  stale_while_revalidate = False
+  token_name = None
+  cache_policy = 'cache_first'
   paginator = {
     'paginationField': None,
     'overrides': None
@@ -27,13 +31,28 @@ class Query:
       ]
     } # yapf: disable
 
-  def __init__( self, params, cacheable=None ):
+  def __init__(
+    self,
+    params,
+    cacheable=None,
+    max_age=None,
+    stale_while_revalidate=None,
+    token_name=None,
+    cache_policy=None
+  ):
     self.params = params.copy()
     self.children = params.get( 'children' )
 
     self.tree = self.create_tree()
     self.string = self.stringify()
     self.cacheable = cacheable if cacheable is not None else self.cacheable
+    self.max_age = max_age if max_age is not None else self.max_age
+    if stale_while_revalidate is not None:
+      self.stale_while_revalidate = stale_while_revalidate
+    # requests for this query use the named token rather than the default one
+    self.token_name = token_name if token_name is not None else self.token_name
+    # one of caching.cache_policies
+    self.cache_policy = cache_policy if cache_policy is not None else self.cache_policy
 
   def update( self, params ):
     assert all([ type(self.params.get(key)) is type(params.get(key)) or params.get(key) is None for key in self.params]), 'Types of values do not match'
@@ -169,11 +188,8 @@ class Actors( Query ):
   fields = [ 'gameID', 'icon', 'id', 'name', 'petOwner', 'server', 'subType', 'type' ]
 
 class RateLimitData( Query ):
-  pass
-
-class PointsSpentThisHour( Query ):
-  parent = RateLimitData
   cacheable = False
+  fields = [ 'limitPerHour', 'pointsSpentThisHour', 'pointsResetIn' ]
 
 class Fights( Query ):
   parent = Report
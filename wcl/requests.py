@@ -1,26 +1,137 @@
 from . import caching, token
+from .query import RateLimitData
 
 import requests
 import json
-from requests.exceptions import HTTPError
+import logging
+import random
+import time
+from requests.exceptions import HTTPError, ConnectionError as RequestsConnectionError, Timeout
+
+log = logging.getLogger( __name__ )
+
+# live requests wait for the hourly points budget to reset once less than
+# `reserve` of it remains. the budget is re-checked at most every `interval` seconds
+rate_limit = {
+  'reserve': 0.05,
+  'interval': 60
+}
+# timeouts, dropped connections, 429 and 5xx responses are retried up to `attempts`
+# times in total, waiting `base_delay` seconds doubled per attempt with +-50% jitter.
+# each attempt gives up after `timeout` seconds without a response
+retry_policy = {
+  'attempts': 4,
+  'base_delay': 1,
+  'timeout': 60
+}
+# each API client has its own budget, so state is kept per token name
+rate_limit_state = {}
+# tokens by name, created on the first live request that uses them so cached work and
+# cache tooling need no credentials. None is the default token
+tokens = {}
+
+def rate_limit_status( token_name=None ):
+  # limitPerHour, pointsSpentThisHour and pointsResetIn as reported by the API
+  rate_limit_state[ token_name ] = {
+    'status': Request( RateLimitData( {}, token_name=token_name ) ).data or {},
+    'checked': time.time()
+  }
+  return rate_limit_state[ token_name ][ 'status' ]
+
+def get_token( name=None ):
+  if name not in tokens:
+    tokens[ name ] = token.Token( name )
+  return tokens[ name ]
+
+def budget_exhausted( status ):
+  if not status or not status.get( 'limitPerHour' ):
+    return False
+  spent = status.get( 'pointsSpentThisHour', 0 )
+  return spent >= status[ 'limitPerHour' ] * ( 1 - rate_limit[ 'reserve' ] )
+
+def warmup( queries, force_refresh=False ):
+  # requests each query in turn so it is cached before it is needed. cached queries
+  # that have not expired are skipped unless `force_refresh` is set. queries the cache
+  # never stores are not requested at all and are counted as uncacheable
+  report = {
+    'loaded': 0,
+    'skipped': 0,
+    'uncacheable': 0,
+    'failed': []
+  }
+  for query in queries:
+    if not query.cacheable or query.cache_policy == 'network_only':
+      log.debug( 'Warmup skipping uncacheable %s', query.string )
+      report[ 'uncacheable' ] += 1
+      continue
+    max_age = query.max_age
+    try:
+      if not force_refresh and caching.probe( query )[ 'hit' ]:
+        report[ 'skipped' ] += 1
+        continue
+      if force_refresh:
+        # an expired entry is refetched and replaced in place, keeping its hits
+        query.max_age = 0
+      Request( query )
+      report[ 'loaded' ] += 1
+    except ( SystemExit, Exception ) as err:
+      # one failing query, whatever the reason, never stops the rest
+      log.warning( 'Warmup failed for %s: %r', query.string, err )
+      report[ 'failed' ].append( ( query.string, err ) )
+    finally:
+      query.max_age = max_age
+  return report
+
+class GraphQLError( RuntimeError ):
+  # raised for responses carrying a GraphQL `errors` array. these are never cached
+  def __init__( self, errors ):
+    self.errors = [
+      {
+        'message': error.get( 'message' ),
+        'path': error.get( 'path' ),
+        'extensions': error.get( 'extensions' )
+      } for error in errors
+    ]
+    super().__init__( '; '.join( [
+      error[ 'message' ] if error[ 'path' ] is None else
+      f"{'.'.join( [ str( key ) for key in error[ 'path' ] ] )}: {error[ 'message' ]}"
+      for error in self.errors
+    ] ) )
 
 @caching.cache
 class Request:
   v2_endpoint = 'https://www.warcraftlogs.com/api/v2/client'
-  token = token.Token()
-
-  DEBUG = True
+  # what a request can fail with; the cache serves stale copies in their place. GraphQL
+  # errors are not among them, since a stale copy would hide a broken query
+  failures = ( SystemExit, OSError, ValueError )
 
   def __init__( self, query, data=None ):
     self.query = query
     self.data = self.get_request() if data is None else data
 
-  def get_request( self ):
-    def https_request( retry=0 ):
-      query_string = self.query.stringify()
-
-      if self.DEBUG:
-        print( 'requesting', query_string )
+  def wait_for_budget( self ):
+    # cached responses never reach get_request, so they do not wait here
+    if isinstance( self.query, RateLimitData ):
+      return
+    token_name = self.query.token_name
+    checked = rate_limit_state.get( token_name, {} ).get( 'checked', 0 )
+    if time.time() - checked < rate_limit[ 'interval' ]:
+      return
+
+    status = rate_limit_status( token_name )
+    while budget_exhausted( status ):
+      log.warning( 'Points budget nearly spent, waiting %ss', status.get( 'pointsResetIn' ) )
+      time.sleep( status.get( 'pointsResetIn' ) or rate_limit[ 'interval' ] )
+      status = rate_limit_status( token_name )
+
+  def post( self, query_string ):
+    # anything that is not retryable, including GraphQL errors, goes back to the caller
+    failure = None
+    for attempt in range( retry_policy[ 'attempts' ] ):
+      if attempt:
+        delay = retry_policy[ 'base_delay' ] * 2**( attempt - 1 ) * random.uniform( 0.5, 1.5 )
+        log.warning( 'Request failed (%s), retrying in %.1fs', failure, delay )
+        time.sleep( delay )
       try:
         req = requests.post(
           self.v2_endpoint,
@@ -29,21 +140,46 @@ class Request:
           },
           data={
             'query': query_string
-          }
+          },
+          timeout=retry_policy[ 'timeout' ]
         )
+      except ( RequestsConnectionError, Timeout ) as err:
+        failure = err
+        continue
+      if req.status_code != 429 and req.status_code < 500:
+        return req
+      failure = f'HTTP {req.status_code}'
+
+    log.error( 'Request failed after %s attempts: %s', retry_policy[ 'attempts' ], failure )
+    raise SystemExit
 
-        resp = json.loads( req.text )
-        if self.DEBUG:
-          if resp.get( 'error', '' ) == 'Unauthenticated.':
-            if retry:
-              print( 'Failed. Already retried...' )
-              return
-            print( 'Unauthenticated. Attempting to obtain new token and retry...' )
-            self.token.get_token()
-            return https_request( retry=1 )
+  def get_request( self ):
+    self.token = get_token( self.query.token_name )
+    self.wait_for_budget()
+
+    def https_request( retry=0 ):
+      query_string = self.query.stringify()
+
+      self.token.refresh_if_needed()
+
+      log.debug( 'requesting %s', query_string )
+      try:
+        req = self.post( query_string )
+        # a 401 body need not be JSON, so check the status before parsing
+        unauthenticated = req.status_code == 401
+        if not unauthenticated:
+          resp = json.loads( req.text )
+          unauthenticated = resp.get( 'error', '' ) == 'Unauthenticated.'
+        if unauthenticated:
+          if retry:
+            log.error( 'Unauthenticated after retrying with a new token' )
+            raise SystemExit
+          log.warning( 'Unauthenticated. Attempting to obtain new token and retry...' )
+          self.token.get_token()
+          return https_request( retry=1 )
         return resp
       except HTTPError as err:
-        print( f'HTTP error occurred: {err}' )
+        log.error( 'HTTP error occurred: %s', err )
         raise SystemExit
 
     def get_path( node ):
@@ -66,9 +202,8 @@ class Request:
     # print(json.dumps(resp, indent=2))
 
     if resp.get( 'errors' ): # pyright: ignore
-      print( f'Failed to complete {self.query.string}' )
-      print( json.dumps( resp, indent=2 ) )
-      raise SystemExit
+      log.error( 'Failed to complete %s\n%s', self.query.string, json.dumps( resp, indent=2 ) )
+      raise GraphQLError( resp.get( 'errors' ) ) # pyright: ignore
 
     path = get_path( self.query.tree )
     body = drill_down(
@@ -1,24 +1,90 @@
+import os
+import re
 import time
 import json
 import pickle
+import logging
 
 from requests.exceptions import HTTPError
 from oauthlib.oauth2 import BackendApplicationClient
 from requests_oauthlib import OAuth2Session
 
+log = logging.getLogger( __name__ )
+
+# tokens within `margin` seconds of expiry are refreshed before each request, by calling
+# `refresh_fn( token )` if it is set and fetching a new token otherwise. refresh_fn
+# returns a token object like the one stored in the token file; if it returns anything
+# without an access_token, token_type and expires_at, a new token is fetched instead
+refresh_policy = {
+  'margin': 60 * 60 * 24,
+  'refresh_fn': None
+}
+
+def usable_token( token ):
+  # load_token and the expiry check need all of these
+  return isinstance( token, dict ) and all( [
+    token.get( key ) for key in ( 'access_token', 'token_type', 'expires_at' )
+  ] )
+
 class Token:
   token_url = 'https://www.warcraftlogs.com/oauth/token'
-  token_filename = 'token.tk'
+  token_filename = os.environ.get( 'CRONCH_TOKEN_PATH', 'token.tk' )
+  token_variable = 'CRONCH_TOKEN'
   credentials_filename = 'credentials'
 
-  def __init__( self ):
+  def __init__( self, name=None ):
+    # a named token keeps its own `<name>.tk` beside the default token file, and reads
+    # `CRONCH_TOKEN_<NAME>`, `credentials.<name>` and `WCL_CLIENT_ID_<NAME>` /
+    # `WCL_CLIENT_SECRET_<NAME>` in place of the unnamed ones
+    # names end up in file and environment variable names, so only letters, digits and
+    # underscores are allowed, and never one whose file is the default token's
+    self.name = name
+    self.variable_suffix = '' if name is None else '_' + name.upper()
+    if name is not None:
+      if not re.fullmatch( r'[A-Za-z0-9_]+', name ):
+        raise ValueError( f'{name!r} is not a valid token name' )
+      self.token_filename = os.path.join( os.path.dirname( self.token_filename ), name + '.tk' )
+      if self.token_filename == Token.token_filename:
+        raise ValueError( f'token {name!r} would share its file with the default token' )
+      self.token_variable = self.token_variable + self.variable_suffix
+      self.credentials_filename = self.credentials_filename + '.' + name
+
     self.read_token()
 
-    if self.token is None:
-      self.get_token()
+    if self.token is None or self.is_expired():
+      self.refresh()
 
-    if self.expiry - 60 * 60 * 24 < time.time():
+  def __repr__( self ):
+    # never includes the access token, so tokens can be logged safely
+    return f'Token(name={self.name!r}, expires_at={getattr( self, "expiry", None )!r})'
+
+  def is_expired( self, margin=None ):
+    # treat tokens within `margin` seconds of expiry as already expired
+    margin = refresh_policy[ 'margin' ] if margin is None else margin
+    return self.expiry - margin < time.time()
+
+  def refresh_if_needed( self ):
+    if self.is_expired():
+      self.refresh()
+
+  def refresh( self ):
+    # refresh_fn renews a loaded token, so without one a new token is fetched
+    if refresh_policy[ 'refresh_fn' ] is None or self.token is None:
+      self.get_token()
+      return
+    token = refresh_policy[ 'refresh_fn' ]( self )
+    # anything unusable would overwrite the token file and refresh again every request
+    if not usable_token( token ):
+      log.warning( 'refresh_fn returned an unusable token, fetching a new one' )
       self.get_token()
+      return
+    self.token = token
+    self.load_token()
+    self.write_token()
+
+  def remaining_lifetime( self ):
+    # seconds until the token actually expires, ignoring any margin
+    return max( self.expiry - time.time(), 0 )
 
   def load_token( self ):
     if self.token:
@@ -26,13 +92,37 @@ class Token:
       self.auth = self.token.get( 'token_type' ) + ' ' + self.token.get( 'access_token' )
 
   def read_token( self ):
+    # a token in the environment takes precedence over the token file
+    if not self.read_token_from_env( self.token_variable ):
+      self.read_token_from_file()
+
+  def read_token_from_env( self, variable ):
+    # the variable holds the same JSON object that is written to the token file
+    self.token = None
+    if not os.environ.get( variable ):
+      return False
+    try:
+      token = json.loads( os.environ[ variable ] )
+      if not usable_token( token ):
+        raise ValueError( 'access_token, token_type or expires_at is missing' )
+      self.token = token
+      self.load_token()
+      return True
+    except Exception as err:
+      log.warning( 'Failed to load token from %s: %s', variable, err )
+      self.token = None
+      return False
+
+  def read_token_from_file( self ):
     try:
       with open( self.token_filename, 'r' ) as handle:
         data = json.load( handle )
+      if not usable_token( data ):
+        raise ValueError( 'access_token, token_type or expires_at is missing' )
       self.token = data
       self.load_token()
     except Exception as err:
-      print( f'Failed to load token: {err}' )
+      log.warning( 'Failed to load token: %s', err )
       self.token = None
 
   def write_token( self ):
@@ -41,6 +131,12 @@ class Token:
       handle.write( json_object )
 
   def read_credentials( self ):
+    # environment variables take precedence over the credentials file
+    client_id = os.environ.get( 'WCL_CLIENT_ID' + self.variable_suffix )
+    client_secret = os.environ.get( 'WCL_CLIENT_SECRET' + self.variable_suffix )
+    if client_id and client_secret:
+      return client_id, client_secret
+
     with open( self.credentials_filename, 'rb' ) as handle:
       data = json.load( handle )
     return data.get('clientID'), data.get('clientSecret')
@@ -58,8 +154,8 @@ class Token:
       self.load_token()
       self.write_token()
     except HTTPError as http_err:
-      print( f'HTTP error occurred: {http_err}' )
+      log.error( 'HTTP error occurred: %s', http_err )
       raise SystemExit
     except Exception as err:
-      print( f'Other error occurred: {err}' )
+      log.error( 'Other error occurred: %s', err )
       raise SystemExit